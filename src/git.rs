@@ -1,75 +1,159 @@
 use crate::Error;
-use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::build::CheckoutBuilder;
 use git2::{Config, Cred, FetchOptions, Repository};
 use git2_credentials;
 use snafu::ResultExt;
 use std::path::Path;
 
-/// clone a repository at a rev to a directory
-// TODO id the directory is already present then fetch and rebase (if not in offline mode)
+/// how to authenticate against the remote, in the order `SourceLoc::download_with` prefers them
+pub enum Credentials<'a> {
+    /// a forge API token (e.g. a GitHub/ForgeJo personal access token), sent as expected for
+    /// HTTPS token auth (`x-access-token` as the username)
+    Token(&'a str),
+    /// a plain username/password pair
+    UserPass(&'a str, &'a str),
+}
+
+/// fetches `url` into a shared bare "database" clone, then materializes `rev` as a clean
+/// worktree at `dst`, returning the resolved commit sha.
+///
+/// this follows cargo's model for its git source cache: one bare clone per repository is
+/// ever fetched into, so a force-pushed branch or a dirty worktree from a previous run can
+/// never leave the database in a conflicted state; each `rev` is then just a cheap hard-reset
+/// checkout of the shared object db into its own worktree directory, which is wiped first so
+/// stale files from a previous checkout never linger.
+///
+/// `accept_invalid_certs` skips TLS certificate validation (for self-signed certificate
+/// servers) and `disable_proxy` bypasses the system's proxy auto-detection.
+#[allow(clippy::too_many_arguments)]
 pub fn retrieve<P, U, R>(
     dst: P,
     url: U,
     rev: R,
-    credentials: Option<(&str, &str)>,
-) -> Result<(), Error>
+    credentials: Option<Credentials<'_>>,
+    depth: Option<u32>,
+    accept_invalid_certs: bool,
+    disable_proxy: bool,
+) -> Result<String, Error>
 where
     P: AsRef<Path>,
     R: AsRef<str>,
     U: AsRef<str>,
 {
     let dst = dst.as_ref();
-    let mut fo = make_fetch_options(credentials).context(crate::GitRetrieve {
-        dst: dst.to_path_buf(),
+    let db_dst = bare_db_path(dst);
+    fetch_bare(
+        &db_dst,
+        url.as_ref(),
+        credentials,
+        depth,
+        accept_invalid_certs,
+        disable_proxy,
+    )
+    .context(crate::GitRetrieve {
+        dst: db_dst.clone(),
+        url: url.as_ref().to_owned(),
+        rev: rev.as_ref().to_owned(),
+    })?;
+    let oid = resolve_oid(&db_dst, rev.as_ref()).context(crate::GitRetrieve {
+        dst: db_dst.clone(),
         url: url.as_ref().to_owned(),
         rev: rev.as_ref().to_owned(),
     })?;
     if dst.exists() {
-        checkout(dst, &rev).context(crate::GitRetrieve {
-            dst: dst.to_path_buf(),
-            url: url.as_ref().to_owned(),
-            rev: rev.as_ref().to_owned(),
+        std::fs::remove_dir_all(dst).context(crate::RemoveFolder {
+            path: dst.to_path_buf(),
         })?;
-        pull(dst, &rev, &mut fo).context(crate::GitRetrieve {
-            dst: dst.to_path_buf(),
-            url: url.as_ref().to_owned(),
-            rev: rev.as_ref().to_owned(),
-        })?;
-    //until pull is fixed and work as expected
-    // let mut tmp = dst.to_path_buf().clone();
-    // tmp.set_extension("part");
-    // if tmp.exists() {
-    //     std::fs::remove_dir_all(&tmp)?;
-    // }
-    // clone(&tmp, url, "master", fo)?;
-    // checkout(&tmp, rev)?;
-    // std::fs::remove_dir_all(&dst)?;
-    // std::fs::rename(&tmp, &dst)?;
+    }
+    std::fs::create_dir_all(dst).context(crate::CreateFolder {
+        path: dst.to_path_buf(),
+    })?;
+    checkout_worktree(&db_dst, dst, oid).context(crate::GitRetrieve {
+        dst: dst.to_path_buf(),
+        url: url.as_ref().to_owned(),
+        rev: rev.as_ref().to_owned(),
+    })?;
+    Ok(oid.to_string())
+}
+
+/// the bare database clone backing the worktree at `dst`: one per `(host, path)`, shared by
+/// every `rev` checked out under the same parent folder (see `SourceLoc::remote_as_local`)
+fn bare_db_path(dst: &Path) -> std::path::PathBuf {
+    match dst.parent() {
+        Some(parent) => parent.join("_db.git"),
+        None => dst.with_extension("git"),
+    }
+}
+
+/// fetches every branch and tag of `url` into the bare repository at `db_dst`,
+/// creating it first if this is the first fetch; the remote's url is (re)set to `url`
+/// on every call so a source that moved (or changed scheme) since the db was created
+/// isn't silently fetched from its old location
+fn fetch_bare(
+    db_dst: &Path,
+    url: &str,
+    credentials: Option<Credentials<'_>>,
+    depth: Option<u32>,
+    accept_invalid_certs: bool,
+    disable_proxy: bool,
+) -> Result<(), git2::Error> {
+    let repository = match Repository::open_bare(db_dst) {
+        Ok(repository) => repository,
+        Err(_) => Repository::init_bare(db_dst)?,
+    };
+    if repository.find_remote("origin").is_err() {
+        repository.remote("origin", url)?;
     } else {
-        clone(&dst, &url, "master", fo)?;
-        checkout(&dst, &rev).context(crate::GitRetrieve {
-            dst: dst.to_path_buf(),
-            url: url.as_ref().to_owned(),
-            rev: rev.as_ref().to_owned(),
-        })?;
+        repository.remote_set_url("origin", url)?;
     }
+    let mut remote = repository.find_remote("origin")?;
+    let mut fo = make_fetch_options(credentials, depth, accept_invalid_certs, disable_proxy)?;
+    remote.fetch(
+        &["+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"],
+        Some(&mut fo),
+        None,
+    )?;
     Ok(())
 }
 
+/// resolves `rev` against the bare database at `db_dst` to a concrete commit oid
+fn resolve_oid(db_dst: &Path, rev: &str) -> Result<git2::Oid, git2::Error> {
+    let repository = Repository::open_bare(db_dst)?;
+    let commit = repository.revparse_single(rev)?.peel_to_commit()?;
+    Ok(commit.id())
+}
+
+/// hard-resets a clean worktree of `oid` at `dst`, as checked out from the bare database at
+/// `db_dst`; `dst` is expected to already exist and be empty (see `retrieve`)
+fn checkout_worktree(db_dst: &Path, dst: &Path, oid: git2::Oid) -> Result<(), git2::Error> {
+    let repository = Repository::open_bare(db_dst)?;
+    let commit = repository.find_commit(oid)?;
+    let tree = commit.tree()?;
+    let mut co = CheckoutBuilder::new();
+    co.force()
+        .remove_ignored(true)
+        .remove_untracked(true)
+        .target_dir(dst);
+    repository.checkout_tree(tree.as_object(), Some(&mut co))
+}
+
 /// a best attempt effort is made to authenticate
 /// requests when required to support private
 /// git repositories
 fn make_fetch_options<'a>(
-    credentials: Option<(&'a str, &'a str)>,
+    credentials: Option<Credentials<'a>>,
+    depth: Option<u32>,
+    accept_invalid_certs: bool,
+    disable_proxy: bool,
 ) -> Result<FetchOptions<'a>, git2::Error> {
     let mut cb = git2::RemoteCallbacks::new();
 
     match credentials {
-        Some(creds) => {
-            cb.credentials(move |_, _, _| {
-                let credentials = Cred::userpass_plaintext(creds.0, creds.1)?;
-                Ok(credentials)
-            });
+        Some(Credentials::Token(token)) => {
+            cb.credentials(move |_, _, _| Cred::userpass_plaintext("x-access-token", token));
+        }
+        Some(Credentials::UserPass(usr, pwd)) => {
+            cb.credentials(move |_, _, _| Cred::userpass_plaintext(usr, pwd));
         }
         None => {
             let git_config = git2::Config::open_default()?;
@@ -80,84 +164,220 @@ fn make_fetch_options<'a>(
         }
     }
 
+    // for self-signed certificate servers: accept whatever certificate is presented
+    // instead of failing the TLS handshake
+    if accept_invalid_certs {
+        cb.certificate_check(|_cert, _host| true);
+    }
+
     let mut fo = FetchOptions::new();
-    let mut proxy_options = git2::ProxyOptions::new();
-    proxy_options.auto();
-    fo.proxy_options(proxy_options)
-        .remote_callbacks(cb)
+    if !disable_proxy {
+        let mut proxy_options = git2::ProxyOptions::new();
+        proxy_options.auto();
+        fo.proxy_options(proxy_options);
+    }
+    fo.remote_callbacks(cb)
         .download_tags(git2::AutotagOption::All)
         .update_fetchhead(true);
+    // a shallow clone/fetch can not be rev-parsed to an arbitrary older commit,
+    // so callers that need history beyond the requested rev should leave depth unset
+    if let Some(depth) = depth {
+        fo.depth(depth as i32);
+    }
     Ok(fo)
 }
 
-fn clone<P, U, R>(dst: P, url: U, rev: R, fo: FetchOptions<'_>) -> Result<(), Error>
+/// resolve `rev` (a branch, tag, or sha) against the bare database backing `dst` to the
+/// concrete commit sha it points at, so callers can pin a template to a reproducible commit
+pub fn resolve_rev<P, R>(dst: P, rev: R) -> Result<String, git2::Error>
 where
     P: AsRef<Path>,
     R: AsRef<str>,
-    U: AsRef<str>,
 {
-    println!(
-        "dst: {}, url: {}",
-        dst.as_ref().to_str().unwrap(),
-        url.as_ref()
-    );
-    std::fs::create_dir_all(&dst.as_ref()).context(crate::CreateFolder {
-        path: dst.as_ref().to_path_buf(),
-    })?;
-    RepoBuilder::new()
-        .branch(rev.as_ref())
-        .fetch_options(fo)
-        .clone(url.as_ref(), dst.as_ref())
-        .context(crate::GitRetrieve {
-            dst: dst.as_ref().to_path_buf(),
-            url: url.as_ref().to_owned(),
-            rev: rev.as_ref().to_owned(),
-        })?;
-    Ok(())
+    let oid = resolve_oid(&bare_db_path(dst.as_ref()), rev.as_ref())?;
+    Ok(oid.to_string())
 }
 
-// see https://stackoverflow.com/questions/54100789/how-is-git-pull-done-with-the-git2-rs-rust-crate
-fn pull<'a, P, R>(dst: P, rev: R, fo: &mut FetchOptions<'a>) -> Result<(), git2::Error>
-where
-    P: AsRef<Path>,
-    R: AsRef<str>,
-{
-    let repository = Repository::discover(dst.as_ref())?;
+/// kind can be "merge" or "diff"
+pub fn find_cmd_tool(kind: &str) -> Result<String, git2::Error> {
+    let config = Config::open_default()?;
+    let tool = config.get_string(&format!("{}.tool", kind))?;
+    config.get_string(&format!("{}tool.{}.cmd", kind, tool))
+}
 
-    // fetch
-    let revref = rev.as_ref();
-    let mut remote = repository.find_remote("origin")?;
-    remote.fetch(&[revref], Some(fo), None)?;
-    remote.disconnect()?;
+/// the git operations `SourceLoc` needs, abstracted so tests can exercise the credential
+/// selection, offline handling and failure cleanup of `download` with a `MockGitBackend`,
+/// without a network or a real git repository (the mock does no fetch or checkout itself)
+pub trait GitBackend {
+    /// fetches and checks out `rev`, returning the resolved commit sha (see `retrieve`)
+    #[allow(clippy::too_many_arguments)]
+    fn retrieve(
+        &self,
+        dst: &Path,
+        url: &str,
+        rev: &str,
+        credentials: Option<Credentials<'_>>,
+        depth: Option<u32>,
+        accept_invalid_certs: bool,
+        disable_proxy: bool,
+    ) -> Result<String, Error>;
 
-    // merge
-    let reference = repository.find_reference("FETCH_HEAD")?;
-    let fetch_head_commit = repository.reference_to_annotated_commit(&reference)?;
-    repository.merge(&[&fetch_head_commit], None, None)?;
-    repository.cleanup_state()?;
+    fn resolve_rev(&self, dst: &Path, rev: &str) -> Result<String, Error>;
 
-    Ok(())
+    fn find_cmd_tool(&self, kind: &str) -> Result<String, Error>;
 }
 
-fn checkout<P, R>(dst: P, rev: R) -> Result<(), git2::Error>
-where
-    P: AsRef<Path>,
-    R: AsRef<str>,
-{
-    let rev = rev.as_ref();
-    let repository = Repository::discover(dst.as_ref())?;
-    let mut co = CheckoutBuilder::new();
-    co.force().remove_ignored(true).remove_untracked(true);
-    let treeish = repository.revparse_single(rev)?;
-    repository.checkout_tree(&treeish, Some(&mut co))?;
+/// the default `GitBackend`, backed by the real git2 functions above
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Git2Backend;
+
+impl GitBackend for Git2Backend {
+    fn retrieve(
+        &self,
+        dst: &Path,
+        url: &str,
+        rev: &str,
+        credentials: Option<Credentials<'_>>,
+        depth: Option<u32>,
+        accept_invalid_certs: bool,
+        disable_proxy: bool,
+    ) -> Result<String, Error> {
+        retrieve(
+            dst,
+            url,
+            rev,
+            credentials,
+            depth,
+            accept_invalid_certs,
+            disable_proxy,
+        )
+    }
+
+    fn resolve_rev(&self, dst: &Path, rev: &str) -> Result<String, Error> {
+        resolve_rev(dst, rev).context(crate::GitRetrieve {
+            dst: dst.to_path_buf(),
+            url: String::new(),
+            rev: rev.to_owned(),
+        })
+    }
+
+    fn find_cmd_tool(&self, kind: &str) -> Result<String, Error> {
+        find_cmd_tool(kind).context(crate::GitRetrieve {
+            dst: std::path::PathBuf::new(),
+            url: String::new(),
+            rev: kind.to_owned(),
+        })
+    }
+}
+
+/// a call that a `MockGitBackend` recorded instead of acting on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedCall {
+    pub url: String,
+    pub rev: String,
+    pub creds: Option<(String, String)>,
+}
+
+/// an in-memory `GitBackend` double: records every `(url, rev, creds)` it is asked to retrieve,
+/// and materializes a caller-supplied directory tree at `dst` to stand in for a real clone
+#[derive(Default)]
+pub struct MockGitBackend {
+    /// directory copied to `dst` on a successful `retrieve`
+    pub fixture: Option<std::path::PathBuf>,
+    /// sha returned by `retrieve` and `resolve_rev`; must be set to a real-looking sha
+    /// before exercising `retrieve` (the `Default::default()` empty string only makes
+    /// sense paired with `fail_with`, and is otherwise rejected with an error, since a
+    /// caller like `SourceLoc::download_with` would use it to build a bogus cache path)
+    pub resolved_rev: String,
+    /// makes `retrieve` fail with this message instead of succeeding
+    pub fail_with: Option<String>,
+    calls: std::cell::RefCell<Vec<RecordedCall>>,
+}
+
+impl MockGitBackend {
+    pub fn calls(&self) -> Vec<RecordedCall> {
+        self.calls.borrow().clone()
+    }
+
+    fn record(&self, url: &str, rev: &str, credentials: &Option<Credentials<'_>>) {
+        let creds = credentials.as_ref().map(|c| match c {
+            Credentials::Token(token) => ("token".to_owned(), (*token).to_owned()),
+            Credentials::UserPass(usr, pwd) => ((*usr).to_owned(), (*pwd).to_owned()),
+        });
+        self.calls.borrow_mut().push(RecordedCall {
+            url: url.to_owned(),
+            rev: rev.to_owned(),
+            creds,
+        });
+    }
+
+    fn materialize(&self, dst: &Path) -> Result<(), Error> {
+        match &self.fixture {
+            Some(fixture) => copy_dir_all(fixture, dst),
+            None => std::fs::create_dir_all(dst).context(crate::CreateFolder {
+                path: dst.to_path_buf(),
+            }),
+        }
+    }
+}
+
+fn copy_dir_all(src: &Path, dst: &Path) -> Result<(), Error> {
+    std::fs::create_dir_all(dst).context(crate::CreateFolder {
+        path: dst.to_path_buf(),
+    })?;
+    for entry in std::fs::read_dir(src).context(crate::error::Io {})? {
+        let entry = entry.context(crate::error::Io {})?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type().context(crate::error::Io {})?.is_dir() {
+            copy_dir_all(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target).context(crate::error::Io {})?;
+        }
+    }
     Ok(())
 }
 
-/// kind can be "merge" or "diff"
-pub fn find_cmd_tool(kind: &str) -> Result<String, git2::Error> {
-    let config = Config::open_default()?;
-    let tool = config.get_string(&format!("{}.tool", kind))?;
-    config.get_string(&format!("{}tool.{}.cmd", kind, tool))
+impl GitBackend for MockGitBackend {
+    fn retrieve(
+        &self,
+        dst: &Path,
+        url: &str,
+        rev: &str,
+        credentials: Option<Credentials<'_>>,
+        _depth: Option<u32>,
+        _accept_invalid_certs: bool,
+        _disable_proxy: bool,
+    ) -> Result<String, Error> {
+        self.record(url, rev, &credentials);
+        if let Some(msg) = &self.fail_with {
+            return Err(git2::Error::from_str(msg)).context(crate::GitRetrieve {
+                dst: dst.to_path_buf(),
+                url: url.to_owned(),
+                rev: rev.to_owned(),
+            });
+        }
+        if self.resolved_rev.is_empty() {
+            return Err(git2::Error::from_str(
+                "MockGitBackend::resolved_rev must be set to a sha before retrieve succeeds; \
+                 the default empty value is only meaningful together with fail_with",
+            ))
+            .context(crate::GitRetrieve {
+                dst: dst.to_path_buf(),
+                url: url.to_owned(),
+                rev: rev.to_owned(),
+            });
+        }
+        self.materialize(dst)?;
+        Ok(self.resolved_rev.clone())
+    }
+
+    fn resolve_rev(&self, _dst: &Path, _rev: &str) -> Result<String, Error> {
+        Ok(self.resolved_rev.clone())
+    }
+
+    fn find_cmd_tool(&self, _kind: &str) -> Result<String, Error> {
+        Ok(String::new())
+    }
 }
 
 #[cfg(test)]
@@ -209,7 +429,7 @@ mod tests {
         assert_eq!(code, 0);
 
         let dst_path = tmp_dir.path().join("dst");
-        retrieve(&dst_path, src_path.to_str().unwrap(), "master", None)?;
+        retrieve(&dst_path, src_path.to_str().unwrap(), "master", None, None, false, false)?;
         assert_eq!(
             fs::read_to_string(&dst_path.join("foo.txt"))?,
             "v1: Lorem ipsum\n"
@@ -234,7 +454,7 @@ mod tests {
         }
         assert_eq!(code, 0);
 
-        retrieve(&dst_path, src_path.to_str().unwrap(), "master", None)?;
+        retrieve(&dst_path, src_path.to_str().unwrap(), "master", None, None, false, false)?;
         assert_eq!(
             fs::read_to_string(&dst_path.join("foo.txt"))?,
             "v2: Hello\n"
@@ -259,7 +479,7 @@ mod tests {
         }
         assert_eq!(code, 0);
 
-        retrieve(&dst_path, src_path.to_str().unwrap(), "master", None)?;
+        retrieve(&dst_path, src_path.to_str().unwrap(), "master", None, None, false, false)?;
         assert_eq!(
             fs::read_to_string(&dst_path.join("foo.txt"))?,
             "v3: Hourra\n"
@@ -268,4 +488,67 @@ mod tests {
         fs::remove_dir_all(tmp_dir)?;
         Ok(())
     }
+
+    #[test]
+    fn mock_git_backend_records_url_rev_and_token_creds() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let tmp_dir = tempdir()?;
+        let dst = tmp_dir.path().join("dst");
+        let mock = MockGitBackend {
+            resolved_rev: "deadbeefdeadbeefdeadbeefdeadbeefdeadbeef".to_owned(),
+            ..Default::default()
+        };
+
+        mock.retrieve(
+            &dst,
+            "https://example.test/org/repo.git",
+            "master",
+            Some(Credentials::Token("sekret")),
+            None,
+            false,
+            false,
+        )?;
+
+        assert_eq!(
+            mock.calls(),
+            vec![RecordedCall {
+                url: "https://example.test/org/repo.git".to_owned(),
+                rev: "master".to_owned(),
+                creds: Some(("token".to_owned(), "sekret".to_owned())),
+            }]
+        );
+        assert!(dst.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn mock_git_backend_materializes_fixture() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = tempdir()?;
+        let fixture = tmp_dir.path().join("fixture");
+        fs::create_dir_all(&fixture)?;
+        fs::write(fixture.join("foo.txt"), "hello")?;
+        let dst = tmp_dir.path().join("dst");
+
+        let mock = MockGitBackend {
+            fixture: Some(fixture),
+            resolved_rev: "deadbeefdeadbeefdeadbeefdeadbeefdeadbeef".to_owned(),
+            ..Default::default()
+        };
+        mock.retrieve(&dst, "local", "master", None, None, false, false)?;
+
+        assert_eq!(fs::read_to_string(dst.join("foo.txt"))?, "hello");
+        Ok(())
+    }
+
+    #[test]
+    fn mock_git_backend_can_simulate_a_failure() {
+        let tmp_dir = tempdir().unwrap();
+        let dst = tmp_dir.path().join("dst");
+        let mock = MockGitBackend {
+            fail_with: Some("boom".to_owned()),
+            ..Default::default()
+        };
+
+        assert!(mock.retrieve(&dst, "local", "master", None, None, false, false).is_err());
+    }
 }
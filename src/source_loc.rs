@@ -3,13 +3,115 @@ use crate::source_uri::SourceUri;
 use crate::transform_values::TransformsValues;
 use crate::Ctx;
 use crate::Result;
+use secrecy::{ExposeSecret, Secret};
 use slog::warn;
 use snafu::ResultExt;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 
+/// wraps a git password so it can't leak through `Debug`, `Display`, or an accidental log line;
+/// only `SourceLoc::download` (building git2 credentials) is allowed to see the plaintext
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct SecretString(Secret<String>);
+
+impl SecretString {
+    fn expose_secret(&self) -> &str {
+        self.0.expose_secret()
+    }
+}
+
+impl Default for SecretString {
+    fn default() -> Self {
+        SecretString(Secret::new(String::new()))
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(s: String) -> Self {
+        SecretString(Secret::new(s))
+    }
+}
+
+impl FromStr for SecretString {
+    type Err = std::convert::Infallible;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(SecretString::from(s.to_owned()))
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "***")
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "***")
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &Self) -> bool {
+        self.expose_secret() == other.expose_secret()
+    }
+}
+
+impl Eq for SecretString {}
+
+impl PartialOrd for SecretString {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SecretString {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.expose_secret().cmp(other.expose_secret())
+    }
+}
+
+impl Hash for SecretString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.expose_secret().hash(state)
+    }
+}
+
+/// name of the lockfile written next to the generated output, pinning each
+/// source's `rev` to the exact commit that was applied
+const LOCK_FILE_NAME: &str = "ffizer.lock";
+
+/// `uri -> resolved commit sha` record, serialized as toml (cargo's `Cargo.lock` style)
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct Lock {
+    #[serde(default)]
+    templates: BTreeMap<String, String>,
+}
+
+fn read_lock(dst_folder: &Path) -> Result<Lock> {
+    let lock_path = dst_folder.join(LOCK_FILE_NAME);
+    if !lock_path.exists() {
+        return Ok(Lock::default());
+    }
+    let content = fs::read_to_string(&lock_path).context(crate::error::Io {})?;
+    toml::from_str(&content).context(crate::LockParsing { path: lock_path })
+}
+
+fn write_lock(dst_folder: &Path, lock: &Lock) -> Result<()> {
+    let lock_path = dst_folder.join(LOCK_FILE_NAME);
+    let content = toml::to_string_pretty(lock).context(crate::LockFormatting {})?;
+    fs::write(&lock_path, content).context(crate::error::Io {})
+}
+
 #[derive(
     StructOpt, Debug, Default, Clone, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, Builder,
 )]
@@ -31,7 +133,12 @@ pub struct SourceLoc {
     pub usr: Option<String>,
     /// git password
     #[structopt(short = "p", long = "password")]
-    pub pwd: Option<String>,
+    pub pwd: Option<SecretString>,
+
+    /// forge API token (GitHub/ForgeJo personal access token), used instead of user/password
+    /// when set; preferred over `usr`/`pwd` by `download`
+    #[structopt(long = "token", env = "FFIZER_GIT_TOKEN", hide_env_values = true)]
+    pub token: Option<SecretString>,
 
     /// path of the folder under the source uri to use for template
     #[structopt(long = "source-subfolder", parse(from_os_str))]
@@ -42,8 +149,20 @@ pub struct SourceLoc {
     pub unsecure_certificate: bool,
 
     /// use to disbale proxy options for git
-    #[structopt(short = "p")]
+    #[structopt(long = "disable-proxy-options")]
     pub disable_proxy_options: bool,
+
+    /// limit fetching to the given number of commits, for a shallow clone
+    /// (a shallow clone can not be rev-parsed to an arbitrary older commit)
+    #[structopt(long = "depth")]
+    pub depth: Option<u32>,
+
+    /// commit sha that `rev` was resolved to on a previous run, read from the
+    /// lockfile; when set (and `update` is not requested), it is used instead
+    /// of `rev` so the same template run reproduces the same output
+    #[structopt(skip)]
+    #[serde(skip)]
+    pub resolved_rev: Option<String>,
 }
 
 impl SourceLoc {
@@ -62,7 +181,7 @@ impl SourceLoc {
     pub fn as_local_path(&self) -> Result<PathBuf> {
         let mut path = match self.uri.host {
             None => self.uri.path.canonicalize().context(crate::error::Io {})?,
-            Some(_) => self.remote_as_local()?,
+            Some(_) => self.remote_as_local(self.effective_rev(false))?,
         };
         if let Some(f) = &self.subfolder {
             path = path.join(f.clone());
@@ -70,8 +189,19 @@ impl SourceLoc {
         Ok(path)
     }
 
+    /// the rev actually used to checkout the template: the locked sha, when one is
+    /// known and a re-resolve wasn't requested, otherwise the configured `rev`
+    fn effective_rev(&self, update: bool) -> &str {
+        if !update {
+            if let Some(resolved_rev) = &self.resolved_rev {
+                return resolved_rev;
+            }
+        }
+        &self.rev
+    }
+
     // the remote_as_local ignore subfolder
-    fn remote_as_local(&self) -> Result<PathBuf> {
+    fn remote_as_local(&self, rev: &str) -> Result<PathBuf> {
         let cache_uri = Self::find_remote_cache_folder()?
             .join(
                 &self
@@ -81,33 +211,97 @@ impl SourceLoc {
                     .unwrap_or_else(|| "no_host".to_owned()),
             )
             .join(&self.uri.path)
-            .join(&self.rev);
+            .join(rev);
         Ok(cache_uri)
     }
 
-    pub fn download(&self, ctx: &Ctx, offline: bool) -> Result<PathBuf> {
+    /// loads the sha that a previous run pinned `self.rev` to, from the lockfile
+    /// next to `dst_folder`, if any
+    pub fn load_resolved_rev(&mut self, dst_folder: &Path) -> Result<()> {
+        let lock = read_lock(dst_folder)?;
+        self.resolved_rev = lock.templates.get(&self.uri.raw).cloned();
+        Ok(())
+    }
+
+    /// records `resolved_rev` for this source in the lockfile next to `dst_folder`
+    fn save_resolved_rev(&self, dst_folder: &Path) -> Result<()> {
+        if let Some(resolved_rev) = &self.resolved_rev {
+            let mut lock = read_lock(dst_folder)?;
+            lock.templates
+                .insert(self.uri.raw.clone(), resolved_rev.clone());
+            write_lock(dst_folder, &lock)?;
+        }
+        Ok(())
+    }
+
+    /// same as `download`, with the real git2-backed `GitBackend`
+    pub fn download(
+        &mut self,
+        ctx: &Ctx,
+        offline: bool,
+        update: bool,
+        dst_folder: &Path,
+    ) -> Result<PathBuf> {
+        self.download_with(ctx, offline, update, dst_folder, &git::Git2Backend)
+    }
+
+    /// downloads (or reuses the cache of) the template and pins it to a resolved commit sha.
+    /// when `update` is `false` and a lockfile entry already exists (see `load_resolved_rev`),
+    /// the locked sha is applied instead of `rev` so the run is reproducible; pass `update: true`
+    /// to re-resolve `rev` and rewrite the lockfile. `backend` performs the actual git
+    /// operations, so tests can substitute a `git::MockGitBackend`.
+    pub fn download_with(
+        &mut self,
+        ctx: &Ctx,
+        offline: bool,
+        update: bool,
+        dst_folder: &Path,
+        backend: &dyn git::GitBackend,
+    ) -> Result<PathBuf> {
+        self.load_resolved_rev(dst_folder)?;
+        let rev = self.effective_rev(update).to_owned();
         if !offline && self.uri.host.is_some() {
-            let remote_path = self.remote_as_local()?;
-            let creds = self.usr.as_ref().map_or(None, |u| {
-                self.pwd
-                    .as_ref()
-                    .map_or(None, |p| Some((u.as_str(), p.as_str())))
-            });
-            if let Err(v) = git::retrieve(
+            let remote_path = self.remote_as_local(&rev)?;
+            let creds = if let Some(token) = &self.token {
+                Some(git::Credentials::Token(token.expose_secret()))
+            } else if let (Some(u), Some(p)) = (&self.usr, &self.pwd) {
+                Some(git::Credentials::UserPass(u.as_str(), p.expose_secret()))
+            } else {
+                None
+            };
+            let resolved_rev = match backend.retrieve(
                 &remote_path,
                 &self.uri.raw,
-                &self.rev,
+                &rev,
                 creds,
-                !self.unsecure_certificate,
-                !self.disable_proxy_options,
+                self.depth,
+                self.unsecure_certificate,
+                self.disable_proxy_options,
             ) {
-                warn!(ctx.logger, "failed to download"; "src" => ?&self, "path" => ?&remote_path, "error" => ?&v);
-                if remote_path.exists() {
-                    fs::remove_dir_all(&remote_path)
-                        .context(crate::RemoveFolder { path: remote_path })?;
+                Ok(resolved_rev) => resolved_rev,
+                Err(v) => {
+                    warn!(ctx.logger, "failed to download"; "src" => ?&self, "path" => ?&remote_path, "error" => ?&v);
+                    if remote_path.exists() {
+                        fs::remove_dir_all(&remote_path)
+                            .context(crate::RemoveFolder { path: remote_path })?;
+                    }
+                    return Err(v);
+                }
+            };
+            // the worktree was checked out under the requested `rev` (e.g. a branch name);
+            // move it to the path keyed by the resolved sha so it matches what
+            // `as_local_path` looks up once `resolved_rev` is set below
+            let resolved_path = self.remote_as_local(&resolved_rev)?;
+            if resolved_path != remote_path {
+                if resolved_path.exists() {
+                    fs::remove_dir_all(&resolved_path).context(crate::RemoveFolder {
+                        path: resolved_path.clone(),
+                    })?;
                 }
-                return Err(v);
+                fs::rename(&remote_path, &resolved_path).context(crate::error::Io {})?;
             }
+            self.resolved_rev = Some(resolved_rev);
+            self.save_resolved_rev(dst_folder)?;
         }
         let path = self.as_local_path()?;
         if !path.exists() {
@@ -138,10 +332,13 @@ impl TransformsValues for SourceLoc {
             uri,
             usr: self.usr.clone(),
             pwd: self.pwd.clone(),
+            token: self.token.clone(),
             rev,
             subfolder,
             unsecure_certificate: self.unsecure_certificate,
             disable_proxy_options: self.disable_proxy_options,
+            depth: self.depth,
+            resolved_rev: self.resolved_rev.clone(),
         })
     }
 }
@@ -160,21 +357,126 @@ impl fmt::Display for SourceLoc {
         )
     }
 }
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use spectral::prelude::*;
-//     use crate::source_uri::SourceUri;
-//     use std::str::FromStr;
-
-//     #[test]
-//     fn as_local_path_on_git() -> Result<()> {
-//         let sut = SourceLoc {
-//             uri: SourceUri::from_str("git@github.com:ffizer/ffizer.git")?,
-//             rev: "master".to_owned(),
-//             subfolder: None,
-//         };
-//         assert_that!(&sut.as_local_path().unwrap()).ends_with("/com.github.ffizer/git/github.com/ffizer/ffizer/master");
-//         Ok(())
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_source_loc(uri: &str) -> SourceLoc {
+        SourceLoc {
+            uri: uri.parse().unwrap(),
+            rev: "master".to_owned(),
+            usr: None,
+            pwd: None,
+            token: None,
+            subfolder: None,
+            unsecure_certificate: false,
+            disable_proxy_options: false,
+            depth: None,
+            resolved_rev: None,
+        }
+    }
+
+    #[test]
+    fn effective_rev_defaults_to_configured_rev_when_no_lock_was_loaded() {
+        let sut = make_source_loc("https://example.test/org/repo.git");
+        assert_eq!(sut.effective_rev(false), "master");
+    }
+
+    #[test]
+    fn load_resolved_rev_prefers_the_locked_sha_over_rev() -> Result<()> {
+        let tmp_dir = tempfile::tempdir().context(crate::error::Io {})?;
+        let mut sut = make_source_loc("https://example.test/org/repo.git");
+        sut.resolved_rev = Some("deadbeef".to_owned());
+        sut.save_resolved_rev(tmp_dir.path())?;
+
+        let mut reloaded = make_source_loc("https://example.test/org/repo.git");
+        reloaded.load_resolved_rev(tmp_dir.path())?;
+
+        assert_eq!(reloaded.effective_rev(false), "deadbeef");
+        // `update: true` re-resolves `rev` instead of trusting the lock
+        assert_eq!(reloaded.effective_rev(true), "master");
+        Ok(())
+    }
+
+    #[test]
+    fn load_resolved_rev_leaves_rev_unchanged_without_a_lock_entry() -> Result<()> {
+        let tmp_dir = tempfile::tempdir().context(crate::error::Io {})?;
+        let mut sut = make_source_loc("https://example.test/org/other.git");
+
+        sut.load_resolved_rev(tmp_dir.path())?;
+
+        assert_eq!(sut.effective_rev(false), "master");
+        Ok(())
+    }
+
+    fn test_ctx() -> Ctx {
+        Ctx {
+            logger: slog::Logger::root(slog::Discard, slog::o!()),
+        }
+    }
+
+    /// isolates `find_remote_cache_folder` to a throwaway directory for the duration of
+    /// `f`, so `download_with` tests never read or write the real user-wide ffizer cache
+    fn with_isolated_cache<F: FnOnce()>(f: F) {
+        let cache_dir = tempfile::tempdir().unwrap();
+        std::env::set_var("XDG_CACHE_HOME", cache_dir.path());
+        f();
+    }
+
+    #[test]
+    fn download_with_offline_does_not_call_the_backend() {
+        with_isolated_cache(|| {
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let mut sut = make_source_loc("https://example.test/org/repo.git");
+            let mock = git::MockGitBackend::default();
+
+            let _ = sut.download_with(&test_ctx(), true, false, tmp_dir.path(), &mock);
+
+            assert!(mock.calls().is_empty());
+        });
+    }
+
+    #[test]
+    fn download_with_cleans_up_remote_path_on_backend_failure() {
+        with_isolated_cache(|| {
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let mut sut = make_source_loc("https://example.test/org/repo.git");
+            let remote_path = sut.remote_as_local(&sut.rev).unwrap();
+            fs::create_dir_all(&remote_path).unwrap();
+            let mock = git::MockGitBackend {
+                fail_with: Some("boom".to_owned()),
+                resolved_rev: "deadbeefdeadbeefdeadbeefdeadbeefdeadbeef".to_owned(),
+                ..Default::default()
+            };
+
+            let result = sut.download_with(&test_ctx(), false, false, tmp_dir.path(), &mock);
+
+            assert!(result.is_err());
+            assert!(!remote_path.exists());
+        });
+    }
+
+    #[test]
+    fn download_with_prefers_token_credentials_over_user_pass() {
+        with_isolated_cache(|| {
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let mut sut = make_source_loc("https://example.test/org/repo.git");
+            sut.usr = Some("alice".to_owned());
+            sut.pwd = Some(SecretString::from("s3cret".to_owned()));
+            sut.token = Some(SecretString::from("a-token".to_owned()));
+            let mock = git::MockGitBackend {
+                resolved_rev: "deadbeefdeadbeefdeadbeefdeadbeefdeadbeef".to_owned(),
+                ..Default::default()
+            };
+
+            let _ = sut.download_with(&test_ctx(), false, false, tmp_dir.path(), &mock);
+
+            let calls = mock.calls();
+            assert_eq!(calls.len(), 1);
+            assert_eq!(
+                calls[0].creds,
+                Some(("token".to_owned(), "a-token".to_owned()))
+            );
+        });
+    }
+}
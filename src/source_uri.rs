@@ -0,0 +1,215 @@
+use crate::transform_values::TransformsValues;
+use crate::Result;
+use git_url_parse::GitUrl;
+use snafu::ResultExt;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// short prefixes expanded to a full uri before parsing, so a template can be
+/// referenced as e.g. `gh:ffizer/template` instead of spelling out the host
+const ALIASES: &[(&str, &str)] = &[("gh:", "https://github.com/"), ("gl:", "https://gitlab.com/")];
+
+fn expand_alias(raw: &str) -> String {
+    for (prefix, expansion) in ALIASES {
+        if let Some(rest) = raw.strip_prefix(prefix) {
+            return format!("{}{}", expansion, rest);
+        }
+    }
+    raw.to_owned()
+}
+
+/// true for forms that are unambiguously a local filesystem path: a dotted or
+/// home-relative path, an absolute unix path, or a windows absolute path with a drive
+/// letter (`C:\...`). These are routed to a local path directly, without going through
+/// `git_url_parse`, because its scp-style (`host:path`) detection would otherwise
+/// misread a windows drive letter as a host
+fn looks_like_a_local_path(raw: &str) -> bool {
+    if raw.starts_with('.') || raw.starts_with('/') || raw.starts_with('~') {
+        return true;
+    }
+    let mut chars = raw.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(drive), Some(':'), Some(sep)) => drive.is_ascii_alphabetic() && (sep == '\\' || sep == '/'),
+        _ => false,
+    }
+}
+
+/// location of a template source, normalized via `git_url_parse` so scp-style
+/// (`git@host:owner/repo.git`), `ssh://`, `https://`, and short-alias (`gh:owner/repo`)
+/// forms all yield the same `(host, path)` split; `path` is `owner/repo` (without a
+/// trailing `.git`) for a remote uri, or the plain filesystem path for a local one.
+/// this split drives the cache layout in `SourceLoc::remote_as_local`
+/// (`<cache>/git/<host>/<owner>/<repo>/<rev>`) and the local-vs-remote decision in
+/// `SourceLoc::as_local_path`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SourceUri {
+    /// the uri exactly as given, before alias expansion; kept as the `Display` form
+    /// and the lockfile key so re-running with the same alias still matches
+    pub raw: String,
+    /// host when this uri points at a remote git repository, `None` for a local path
+    pub host: Option<String>,
+    /// `owner/repo` for a remote uri, or the filesystem path for a local one
+    pub path: PathBuf,
+}
+
+impl FromStr for SourceUri {
+    type Err = crate::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        if looks_like_a_local_path(raw) {
+            return Ok(SourceUri {
+                raw: raw.to_owned(),
+                host: None,
+                path: PathBuf::from(raw),
+            });
+        }
+        let expanded = expand_alias(raw);
+        let git_url = GitUrl::parse(&expanded).context(crate::SourceUriParsing {
+            uri: raw.to_owned(),
+        })?;
+        let path = match &git_url.host {
+            Some(_) => {
+                let mut path = PathBuf::new();
+                if let Some(owner) = &git_url.owner {
+                    path.push(owner);
+                }
+                path.push(&git_url.name);
+                path
+            }
+            None => PathBuf::from(raw),
+        };
+        Ok(SourceUri {
+            raw: raw.to_owned(),
+            host: git_url.host.clone(),
+            path,
+        })
+    }
+}
+
+impl TryFrom<String> for SourceUri {
+    type Error = crate::Error;
+
+    fn try_from(raw: String) -> Result<Self> {
+        raw.parse()
+    }
+}
+
+impl From<SourceUri> for String {
+    fn from(uri: SourceUri) -> String {
+        uri.raw
+    }
+}
+
+impl fmt::Display for SourceUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+impl TransformsValues for SourceUri {
+    fn transforms_values<F>(&self, render: &F) -> Result<SourceUri>
+    where
+        F: Fn(&str) -> String,
+    {
+        render(&self.raw).parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_https() -> Result<()> {
+        let sut = SourceUri::from_str("https://github.com/ffizer/ffizer.git")?;
+        assert_eq!(sut.host, Some("github.com".to_owned()));
+        assert_eq!(sut.path, PathBuf::from("ffizer/ffizer"));
+        Ok(())
+    }
+
+    #[test]
+    fn parses_ssh() -> Result<()> {
+        let sut = SourceUri::from_str("ssh://git@github.com/ffizer/ffizer.git")?;
+        assert_eq!(sut.host, Some("github.com".to_owned()));
+        assert_eq!(sut.path, PathBuf::from("ffizer/ffizer"));
+        Ok(())
+    }
+
+    #[test]
+    fn parses_scp_style() -> Result<()> {
+        let sut = SourceUri::from_str("git@github.com:ffizer/ffizer.git")?;
+        assert_eq!(sut.host, Some("github.com".to_owned()));
+        assert_eq!(sut.path, PathBuf::from("ffizer/ffizer"));
+        Ok(())
+    }
+
+    #[test]
+    fn parses_gh_shorthand() -> Result<()> {
+        let sut = SourceUri::from_str("gh:ffizer/ffizer")?;
+        assert_eq!(sut.host, Some("github.com".to_owned()));
+        assert_eq!(sut.path, PathBuf::from("ffizer/ffizer"));
+        Ok(())
+    }
+
+    #[test]
+    fn falls_back_to_a_local_path() -> Result<()> {
+        let sut = SourceUri::from_str("./templates/foo")?;
+        assert_eq!(sut.host, None);
+        assert_eq!(sut.path, PathBuf::from("./templates/foo"));
+        Ok(())
+    }
+
+    #[test]
+    fn treats_a_bare_relative_path_as_local() -> Result<()> {
+        let sut = SourceUri::from_str("templates/foo")?;
+        assert_eq!(sut.host, None);
+        assert_eq!(sut.path, PathBuf::from("templates/foo"));
+        Ok(())
+    }
+
+    #[test]
+    fn treats_an_absolute_path_as_local() -> Result<()> {
+        let sut = SourceUri::from_str("/abs/path")?;
+        assert_eq!(sut.host, None);
+        assert_eq!(sut.path, PathBuf::from("/abs/path"));
+        Ok(())
+    }
+
+    #[test]
+    fn treats_a_home_relative_path_as_local() -> Result<()> {
+        let sut = SourceUri::from_str("~/templates/foo")?;
+        assert_eq!(sut.host, None);
+        assert_eq!(sut.path, PathBuf::from("~/templates/foo"));
+        Ok(())
+    }
+
+    #[test]
+    fn treats_a_windows_path_as_local() -> Result<()> {
+        let sut = SourceUri::from_str(r"C:\templates\foo")?;
+        assert_eq!(sut.host, None);
+        assert_eq!(sut.path, PathBuf::from(r"C:\templates\foo"));
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_the_raw_uri_through_display() -> Result<()> {
+        for raw in &[
+            "https://github.com/ffizer/ffizer.git",
+            "ssh://git@github.com/ffizer/ffizer.git",
+            "git@github.com:ffizer/ffizer.git",
+            "gh:ffizer/ffizer",
+            "./templates/foo",
+            "templates/foo",
+            "/abs/path",
+            "~/templates/foo",
+            r"C:\templates\foo",
+        ] {
+            let sut = SourceUri::from_str(raw)?;
+            assert_eq!(sut.to_string(), *raw);
+        }
+        Ok(())
+    }
+}